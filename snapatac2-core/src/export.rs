@@ -2,29 +2,120 @@ use crate::utils::{ChromValues, ChromValuesReader, GenomeIndex, GBaseIndex};
 
 use anndata_rs::{element::ElemTrait, anndata::{AnnData, AnnDataSet}};
 use nalgebra_sparse::CsrMatrix;
-use anyhow::{Context, Result, ensure};
-use flate2::{Compression, write::GzEncoder};
+use anyhow::{anyhow, Context, Result};
+use flate2::{Compression, read::MultiGzDecoder, write::GzEncoder};
 use itertools::Itertools;
 use std::{
+    cmp::Reverse,
     fs::File,
     io::{BufReader, BufWriter, BufRead, Write},
     path::{Path, PathBuf},
-    collections::{BTreeMap, HashMap, HashSet},
-    process::Command,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
 };
 use tempfile::Builder;
 use rayon::iter::{ParallelIterator, IntoParallelIterator};
-use which::which;
 use bed_utils::bed::{BEDLike, BED, BedGraph, OptionalFields};
 use bigtools::{bigwig::bigwigwrite::BigWigWrite, bed::bedparser::BedParser};
 use futures::executor::ThreadPool;
 
+/// A per-chromosome sorted set of blacklist intervals (e.g. the ENCODE
+/// artifact blacklist), supporting O(log n) point-overlap queries so it can
+/// be consulted for every insertion without noticeably slowing down export.
+pub struct Blacklist {
+    by_chrom: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Blacklist {
+    pub fn from_bed<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let f = File::open(&path)
+            .with_context(|| format!("cannot open blacklist file: {}", path.as_ref().display()))?;
+        let reader: Box<dyn BufRead> = if path.as_ref().extension().map_or(false, |x| x == "gz") {
+            Box::new(BufReader::new(MultiGzDecoder::new(f)))
+        } else {
+            Box::new(BufReader::new(f))
+        };
+
+        let mut by_chrom: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            let mut fields = line.split('\t');
+            let chrom = fields.next().context("blacklist record is missing chrom field")?;
+            let start: u64 = fields.next()
+                .context("blacklist record is missing start field")?
+                .parse().context("blacklist start is not a valid integer")?;
+            let end: u64 = fields.next()
+                .context("blacklist record is missing end field")?
+                .parse().context("blacklist end is not a valid integer")?;
+            by_chrom.entry(chrom.to_string()).or_insert_with(Vec::new).push((start, end));
+        }
+        // Sort, then merge overlapping/nested intervals so that the O(log n)
+        // lookup in `overlaps` (which only inspects the single predecessor
+        // interval) is valid for arbitrary, possibly-unmerged input.
+        by_chrom.values_mut().for_each(|v| {
+            v.sort_unstable_by_key(|x| x.0);
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(v.len());
+            for &(start, end) in v.iter() {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *v = merged;
+        });
+        Ok(Self { by_chrom })
+    }
+
+    /// Whether `pos` falls inside any blacklist interval on `chrom`.
+    pub fn overlaps(&self, chrom: &str, pos: u64) -> bool {
+        match self.by_chrom.get(chrom) {
+            None => false,
+            Some(intervals) => {
+                let idx = intervals.partition_point(|&(start, _)| start <= pos);
+                idx > 0 && intervals[idx - 1].1 > pos
+            }
+        }
+    }
+}
+
+/// Coverage normalization applied to a bigwig track before it is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormMethod {
+    /// Raw, unnormalized insertion counts.
+    None,
+    /// Counts per million: `value / total * 1e6`.
+    CPM,
+    /// CPM further divided by the bin length in kb.
+    RPKM,
+    /// Bins per million: each bin's length-normalized value (`value /
+    /// bin_length`), rescaled so the sum of all bins' length-normalized
+    /// values is `1e6`.
+    BPM,
+    /// Reads per genomic content: scaled so the average genome-wide
+    /// coverage is 1x, using the effective genome size.
+    RPGC,
+}
+
+impl NormMethod {
+    /// Short tag used to distinguish tracks of different normalization
+    /// modes when they share a filename otherwise.
+    fn tag(&self) -> &'static str {
+        match self {
+            NormMethod::None => "raw",
+            NormMethod::CPM => "CPM",
+            NormMethod::RPKM => "RPKM",
+            NormMethod::BPM => "BPM",
+            NormMethod::RPGC => "RPGC",
+        }
+    }
+}
+
 pub trait Exporter: ChromValuesReader {
     fn export_bed<P: AsRef<Path>>(
         &self,
         barcodes: &Vec<&str>,
         group_by: &Vec<&str>,
         selections: Option<HashSet<&str>>,
+        blacklist: Option<&Blacklist>,
         dir: P,
         prefix: &str,
         suffix:&str,
@@ -35,6 +126,23 @@ pub trait Exporter: ChromValuesReader {
         group_by: &Vec<&str>,
         selections: Option<HashSet<&str>>,
         resolution: usize,
+        normalization: NormMethod,
+        blacklist: Option<&Blacklist>,
+        dir: P,
+        prefix: &str,
+        suffix:&str,
+    ) -> Result<HashMap<String, PathBuf>>;
+
+    /// Export fragments in the canonical 10x-style fragment format
+    /// (`chrom`, `start`, `end`, `barcode`, `count`), one line per unique
+    /// fragment with the duplicate count folded into the `count` column,
+    /// sorted by genomic coordinate and written as bgzip-compressed,
+    /// tabix-indexed `fragments.tsv.gz` files.
+    fn export_fragments<P: AsRef<Path>>(
+        &self,
+        barcodes: &Vec<&str>,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
         dir: P,
         prefix: &str,
         suffix:&str,
@@ -45,51 +153,102 @@ pub trait Exporter: ChromValuesReader {
         q_value: f64,
         group_by: &Vec<&str>,
         selections: Option<HashSet<&str>>,
+        replicate_qc: Option<ReplicateQC>,
+        blacklist: Option<&Blacklist>,
         dir: P,
         prefix: &str,
         suffix:&str,
     ) -> Result<HashMap<String, PathBuf>>
     {
-        // Check if the command is in the PATH
-        ensure!(
-            which("macs2").is_ok(),
-            "Cannot find macs2; please make sure macs2 has been installed"
-        );
-
         std::fs::create_dir_all(&dir)?;
         let tmp_dir = Builder::new().tempdir_in(&dir)
             .context("failed to create tmperorary directory")?;
 
         eprintln!("preparing input...");
         let files = self.export_bed(
-            group_by, group_by, selections, &tmp_dir, "", ".bed.gz"
+            group_by, group_by, selections.clone(), blacklist, &tmp_dir, "", ".bed.gz"
         ).with_context(|| format!("cannot save bed file to {}", tmp_dir.path().display()))?;
-        let genome_size = self.get_reference_seq_info()?.into_iter().map(|(_, v)| v).sum();
+        let chrom_sizes: HashMap<String, u64> = self.get_reference_seq_info()?.into_iter().collect();
+
+        // For the reproducibility filter, partition each group's cells into
+        // `n_replicates` pseudo-replicates (bucketed by a hash of the cell's
+        // position) and route them through the same export_bed/call_peaks
+        // pipeline used for the pooled group.
+        let replicates = replicate_qc.as_ref().map(|qc| -> Result<HashMap<String, Vec<PathBuf>>> {
+            eprintln!("preparing {} pseudo-replicates per group...", qc.n_replicates);
+            const REP_SEP: &str = "@@rep";
+            let replicate_labels: Vec<String> = group_by.iter().enumerate()
+                .map(|(i, g)| format!("{}{}{}", g, REP_SEP, pseudo_random_bucket(i, qc.n_replicates)))
+                .collect();
+            let replicate_group_by: Vec<&str> = replicate_labels.iter().map(|s| s.as_str()).collect();
+            let replicate_files = self.export_bed(
+                &replicate_group_by, &replicate_group_by, None, blacklist, &tmp_dir, "rep_", ".bed.gz",
+            ).context("cannot save pseudo-replicate bed files")?;
+
+            let mut by_group: HashMap<String, Vec<PathBuf>> = HashMap::new();
+            for (label, path) in replicate_files {
+                if let Some((group, _rep)) = label.split_once(REP_SEP) {
+                    by_group.entry(group.to_string()).or_insert_with(Vec::new).push(path);
+                }
+            }
+            by_group.retain(|group, _| files.contains_key(group));
+            Ok(by_group)
+        }).transpose()?;
+
         eprintln!("calling peaks for {} groups...", files.len());
         files.into_par_iter().map(|(key, fl)| {
             let out_file = dir.as_ref().join(
                 prefix.to_string() + key.as_str().replace("/", "+").as_str() + suffix
             );
-            macs2(fl, q_value, genome_size, &tmp_dir, &out_file)?;
+            call_peaks(fl, q_value, &chrom_sizes, &out_file)
+                .with_context(|| format!("failed to call peaks for group: {}", key))?;
+
+            if let (Some(qc), Some(replicate_files)) = (&replicate_qc, &replicates) {
+                if let Some(rep_beds) = replicate_files.get(&key) {
+                    let rep_peaks: Vec<PathBuf> = rep_beds.iter().enumerate().map(|(i, bed)| {
+                        let rep_peak = tmp_dir.path().join(format!("{}.rep{}.peaks", key.replace("/", "+"), i));
+                        call_peaks(bed, q_value, &chrom_sizes, &rep_peak)?;
+                        Ok(rep_peak)
+                    }).collect::<Result<_>>()?;
+                    filter_by_reproducibility(&out_file, &rep_peaks, qc)
+                        .with_context(|| format!("failed to apply reproducibility filter for group: {}", key))?;
+                }
+            }
+
             eprintln!("group {}: done!", key);
             Ok((key, out_file))
         }).collect()
     }
 }
 
+/// Configuration for the bootstrap pseudo-replicate reproducibility filter
+/// applied by [`Exporter::call_peaks`].
+#[derive(Clone, Debug)]
+pub struct ReplicateQC {
+    /// Number of pseudo-replicates each group's cells are partitioned into.
+    pub n_replicates: usize,
+    /// Minimum number of pseudo-replicates a pooled peak must be recovered
+    /// in (by reciprocal overlap) to be retained.
+    pub min_replicates: usize,
+    /// Minimum reciprocal overlap fraction for a pseudo-replicate peak to
+    /// count as recovering a pooled peak.
+    pub overlap_fraction: f64,
+}
+
 impl Exporter for AnnData {
     fn export_bed<P: AsRef<Path>>(
         &self,
         barcodes: &Vec<&str>,
         group_by: &Vec<&str>,
         selections: Option<HashSet<&str>>,
+        blacklist: Option<&Blacklist>,
         dir: P,
         prefix: &str,
         suffix:&str,
     ) -> Result<HashMap<String, PathBuf>> {
         export_insertions_as_bed(
             &mut self.read_insertions(500)?,
-            barcodes, group_by, selections, dir, prefix, suffix,
+            barcodes, group_by, selections, blacklist, dir, prefix, suffix,
         )
     }
 
@@ -98,6 +257,8 @@ impl Exporter for AnnData {
         group_by: &Vec<&str>,
         selections: Option<HashSet<&str>>,
         resolution: usize,
+        normalization: NormMethod,
+        blacklist: Option<&Blacklist>,
         dir: P,
         prefix: &str,
         suffix:&str,
@@ -112,7 +273,8 @@ impl Exporter for AnnData {
             .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
         groups.into_iter().map(|x| {
             let filename = dir.as_ref().join(
-                prefix.to_string() + x.replace("/", "+").as_str() + suffix
+                prefix.to_string() + x.replace("/", "+").as_str()
+                    + "." + normalization.tag() + suffix
             );
             let insertion: Box<CsrMatrix<u8>> = self.get_obsm().inner()
                 .get("insertion").expect(".obsm does not contain key: insertion")
@@ -122,11 +284,28 @@ impl Exporter for AnnData {
                 &genome_index,
                 &chrom_sizes,
                 resolution,
+                normalization,
+                blacklist,
                 filename.as_path().to_str().unwrap().to_string(),
             );
             Ok((x.to_string(), filename))
         }).collect()
     }
+
+    fn export_fragments<P: AsRef<Path>>(
+        &self,
+        barcodes: &Vec<&str>,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
+        dir: P,
+        prefix: &str,
+        suffix:&str,
+    ) -> Result<HashMap<String, PathBuf>> {
+        export_insertions_as_fragments(
+            &mut self.read_insertions(500)?,
+            barcodes, group_by, selections, dir, prefix, suffix,
+        )
+    }
 }
 
 impl Exporter for AnnDataSet {
@@ -135,13 +314,14 @@ impl Exporter for AnnDataSet {
         barcodes: &Vec<&str>,
         group_by: &Vec<&str>,
         selections: Option<HashSet<&str>>,
+        blacklist: Option<&Blacklist>,
         dir: P,
         prefix: &str,
         suffix:&str,
     ) -> Result<HashMap<String, PathBuf>> {
         export_insertions_as_bed(
             &mut self.read_insertions(500)?,
-            barcodes, group_by, selections, dir, prefix, suffix,
+            barcodes, group_by, selections, blacklist, dir, prefix, suffix,
         )
     }
 
@@ -150,6 +330,8 @@ impl Exporter for AnnDataSet {
         group_by: &Vec<&str>,
         selections: Option<HashSet<&str>>,
         resolution: usize,
+        normalization: NormMethod,
+        blacklist: Option<&Blacklist>,
         dir: P,
         prefix: &str,
         suffix:&str,
@@ -164,7 +346,8 @@ impl Exporter for AnnDataSet {
             .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
         groups.into_iter().map(|x| {
             let filename = dir.as_ref().join(
-                prefix.to_string() + x.replace("/", "+").as_str() + suffix
+                prefix.to_string() + x.replace("/", "+").as_str()
+                    + "." + normalization.tag() + suffix
             );
             let insertion: Box<CsrMatrix<u8>> = self.get_obsm().inner()
                 .get("insertion").expect(".obsm does not contain key: insertion")
@@ -174,15 +357,163 @@ impl Exporter for AnnDataSet {
                 &genome_index,
                 &chrom_sizes,
                 resolution,
+                normalization,
+                blacklist,
                 filename.as_path().to_str().unwrap().to_string(),
             );
             Ok((x.to_string(), filename))
         }).collect()
     }
+
+    fn export_fragments<P: AsRef<Path>>(
+        &self,
+        barcodes: &Vec<&str>,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
+        dir: P,
+        prefix: &str,
+        suffix:&str,
+    ) -> Result<HashMap<String, PathBuf>> {
+        export_insertions_as_fragments(
+            &mut self.read_insertions(500)?,
+            barcodes, group_by, selections, dir, prefix, suffix,
+        )
+    }
 }
 
 
 
+/// A single interval read back from a peak file written by
+/// [`Exporter::call_peaks`] (or any sorted BED/narrowPeak file).
+struct PeakRecord {
+    chrom: String,
+    start: u64,
+    end: u64,
+    score: u64,
+}
+
+/// Read the raw (decompressed) lines of a peak/BED file, transparently
+/// handling gzip compression.
+fn read_lines<P: AsRef<Path>>(file: P) -> Result<Vec<String>> {
+    let f = File::open(&file)
+        .with_context(|| format!("cannot open peak file: {}", file.as_ref().display()))?;
+    let reader: Box<dyn BufRead> = if file.as_ref().extension().map_or(false, |x| x == "gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(f)))
+    } else {
+        Box::new(BufReader::new(f))
+    };
+    reader.lines().map(|line| Ok(line?)).collect()
+}
+
+fn parse_peak_record(line: &str) -> Result<PeakRecord> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    Ok(PeakRecord {
+        chrom: fields[0].to_string(),
+        start: fields[1].parse().context("invalid peak start")?,
+        end: fields[2].parse().context("invalid peak end")?,
+        score: fields.get(4).and_then(|s| s.parse().ok()).unwrap_or(0),
+    })
+}
+
+fn read_peak_records<P: AsRef<Path>>(file: P) -> Result<Vec<PeakRecord>> {
+    read_lines(file)?.iter().map(|line| parse_peak_record(line)).collect()
+}
+
+/// Open a peak/BED file as a lazy, line-at-a-time iterator of parsed
+/// records (transparently handling gzip compression), so a caller merging
+/// many such files can hold only one record per file in memory at a time.
+fn peak_record_stream<P: AsRef<Path>>(file: P) -> Result<impl Iterator<Item = Result<PeakRecord>>> {
+    let f = File::open(&file)
+        .with_context(|| format!("cannot open peak file: {}", file.as_ref().display()))?;
+    let reader: Box<dyn BufRead> = if file.as_ref().extension().map_or(false, |x| x == "gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(f)))
+    } else {
+        Box::new(BufReader::new(f))
+    };
+    Ok(reader.lines().map(|line| parse_peak_record(&line?)))
+}
+
+/// Merge the per-group peak sets produced by [`Exporter::call_peaks`] into a
+/// single consensus BED spanning all groups.
+///
+/// This is implemented as a streaming k-way merge over the (already sorted)
+/// per-group peak files rather than loading everything into memory at once:
+/// each input file is held open as a line-at-a-time iterator, records are
+/// popped off a min-heap in `(chrom, start)` order, and a currently-open
+/// interval is extended whenever the next record starts within `max_gap` of
+/// its end, or flushed and replaced otherwise. The merged record carries
+/// the maximum score across the peaks it absorbed and the number of
+/// distinct groups that contributed to it.
+pub fn merge_peaks<P: AsRef<Path>>(
+    peak_files: &HashMap<String, PathBuf>,
+    max_gap: u64,
+    out_file: P,
+) -> Result<()> {
+    let mut streams: Vec<_> = peak_files.values()
+        .map(|f| Ok(peak_record_stream(f)?.peekable()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut heap: BinaryHeap<Reverse<(String, u64, usize)>> = BinaryHeap::new();
+    for (i, s) in streams.iter_mut().enumerate() {
+        if let Some(r) = s.peek() {
+            let r = r.as_ref().map_err(|e| anyhow!("{e}"))?;
+            heap.push(Reverse((r.chrom.clone(), r.start, i)));
+        }
+    }
+
+    struct OpenInterval {
+        chrom: String,
+        start: u64,
+        end: u64,
+        max_score: u64,
+        groups: HashSet<usize>,
+    }
+
+    let mut writer = BufWriter::new(File::create(&out_file)
+        .with_context(|| format!("cannot create file: {}", out_file.as_ref().display()))?);
+    let mut current: Option<OpenInterval> = None;
+    let mut flush = |interval: OpenInterval, writer: &mut BufWriter<File>| -> Result<()> {
+        writeln!(
+            writer, "{}\t{}\t{}\t.\t{}\t.\t{}",
+            interval.chrom, interval.start, interval.end,
+            interval.max_score, interval.groups.len(),
+        )?;
+        Ok(())
+    };
+
+    while let Some(Reverse((_, _, i))) = heap.pop() {
+        let rec = streams[i].next().unwrap()?;
+        if let Some(next) = streams[i].peek() {
+            let next = next.as_ref().map_err(|e| anyhow!("{e}"))?;
+            heap.push(Reverse((next.chrom.clone(), next.start, i)));
+        }
+
+        let merges_with_current = current.as_ref().map_or(false, |cur| {
+            cur.chrom == rec.chrom && rec.start <= cur.end + max_gap
+        });
+        if merges_with_current {
+            let cur = current.as_mut().unwrap();
+            cur.end = cur.end.max(rec.end);
+            cur.max_score = cur.max_score.max(rec.score);
+            cur.groups.insert(i);
+        } else {
+            if let Some(prev) = current.take() {
+                flush(prev, &mut writer)?;
+            }
+            let mut groups = HashSet::new();
+            groups.insert(i);
+            current = Some(OpenInterval {
+                chrom: rec.chrom, start: rec.start, end: rec.end,
+                max_score: rec.score, groups,
+            });
+        }
+    }
+    if let Some(prev) = current.take() {
+        flush(prev, &mut writer)?;
+    }
+    Ok(())
+}
+
 /// Export TN5 insertion sites to bed files with following fields:
 ///     1. chromosome
 ///     2. start
@@ -193,6 +524,7 @@ fn export_insertions_as_bed<I, P>(
     barcodes: &Vec<&str>,
     group_by: &Vec<&str>,
     selections: Option<HashSet<&str>>,
+    blacklist: Option<&Blacklist>,
     dir: P,
     prefix: &str,
     suffix:&str,
@@ -211,7 +543,7 @@ where
         );
         let f = File::create(&filename)
             .with_context(|| format!("cannot create file: {}", filename.display()))?;
-        let e: Box<dyn Write> = if filename.ends_with(".gz") {
+        let e: Box<dyn Write> = if filename.extension().map_or(false, |x| x == "gz") {
             Box::new(GzEncoder::new(BufWriter::new(f), Compression::default()))
         } else {
             Box::new(BufWriter::new(f))
@@ -224,13 +556,15 @@ where
         x.into_iter().enumerate().try_for_each::<_, Result<_>>(|(i, ins)| {
             if let Some((_, fl)) = files.get_mut(group_by[accum + i]) {
                 let bc = barcodes[accum + i];
-                ins.into_iter().map(|x| {
-                    let bed: BED<4> = BED::new(
-                        x.chrom(), x.start(), x.end(), Some(bc.to_string()),
-                        None, None, OptionalFields::default(),
-                    );
-                    vec![bed; x.value as usize]
-                }).flatten().try_for_each(|o| writeln!(fl, "{}", o))?;
+                ins.into_iter()
+                    .filter(|x| blacklist.map_or(true, |b| !b.overlaps(x.chrom(), x.start())))
+                    .map(|x| {
+                        let bed: BED<4> = BED::new(
+                            x.chrom(), x.start(), x.end(), Some(bc.to_string()),
+                            None, None, OptionalFields::default(),
+                        );
+                        vec![bed; x.value as usize]
+                    }).flatten().try_for_each(|o| writeln!(fl, "{}", o))?;
             }
             Ok(())
         })?;
@@ -239,38 +573,355 @@ where
     Ok(files.into_iter().map(|(k, (v, _))| (k.to_string(), v)).collect())
 }
 
+/// Export TN5 insertions in the canonical fragment format (`chrom`,
+/// `start`, `end`, `barcode`, `count`), one line per unique fragment with
+/// the duplicate count folded into the `count` column, sorted by genomic
+/// coordinate and written as a bgzip-compressed, tabix-indexed file.
+///
+/// Unlike [`export_insertions_as_bed`], which streams each group's
+/// insertions straight to disk, this buffers a group's records so they can
+/// be sorted by coordinate before being written, since both bgzip and
+/// tabix require coordinate-sorted input.
+fn export_insertions_as_fragments<I, P>(
+    insertions: &mut I,
+    barcodes: &Vec<&str>,
+    group_by: &Vec<&str>,
+    selections: Option<HashSet<&str>>,
+    dir: P,
+    prefix: &str,
+    suffix:&str,
+) -> Result<HashMap<String, PathBuf>>
+where
+    I: Iterator<Item = Vec<ChromValues>>,
+    P: AsRef<Path>,
+{
+    let mut groups: HashSet<&str> = group_by.iter().map(|x| *x).unique().collect();
+    if let Some(select) = selections { groups.retain(|x| select.contains(x)); }
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
+
+    let mut records: HashMap<&str, Vec<FragmentRecord>> =
+        groups.iter().map(|x| (*x, Vec::new())).collect();
+
+    insertions.try_fold::<_, _, Result<_>>(0, |accum, x| {
+        let n_records = x.len();
+        x.into_iter().enumerate().for_each(|(i, ins)| {
+            if let Some(recs) = records.get_mut(group_by[accum + i]) {
+                let bc = barcodes[accum + i];
+                recs.extend(ins.into_iter().map(|x| FragmentRecord {
+                    chrom: x.chrom().to_string(),
+                    start: x.start(),
+                    end: x.end(),
+                    barcode: bc.to_string(),
+                    count: x.value as u32,
+                }));
+            }
+        });
+        Ok(accum + n_records)
+    })?;
+
+    records.into_iter().map(|(group, mut recs)| {
+        recs.sort_unstable_by(|a, b| a.chrom.cmp(&b.chrom).then(a.start.cmp(&b.start)));
+        let filename = dir.as_ref().join(
+            prefix.to_string() + group.replace("/", "+").as_str() + suffix
+        );
+        write_fragments_bgzf(&recs, &filename)
+            .with_context(|| format!("failed to write fragment file: {}", filename.display()))?;
+        Ok((group.to_string(), filename))
+    }).collect()
+}
+
+struct FragmentRecord {
+    chrom: String,
+    start: u64,
+    end: u64,
+    barcode: String,
+    count: u32,
+}
+
+/// Maximum amount of uncompressed data packed into a single BGZF block.
+const BGZF_BLOCK_SIZE: usize = 65280;
+
+/// The fixed 28-byte BGZF end-of-file marker (an empty gzip block).
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00,
+    0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+
+/// A minimal BGZF (blocked gzip, as used by bgzip/tabix) writer. Each block
+/// is an independent gzip member with a `BC` extra field recording its
+/// compressed size, which is what allows compliant readers to seek to an
+/// arbitrary block. [`BgzfWriter::voffset`] exposes the current BGZF
+/// virtual file offset (`block_coffset << 16 | offset_in_block`), which is
+/// the coordinate system tabix indices are built on.
+struct BgzfWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    compressed_bytes_written: u64,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, buf: Vec::with_capacity(BGZF_BLOCK_SIZE), compressed_bytes_written: 0 }
+    }
+
+    fn voffset(&self) -> u64 {
+        (self.compressed_bytes_written << 16) | self.buf.len() as u64
+    }
+
+    fn flush_block(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() { return Ok(()); }
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(BGZF_BLOCK_SIZE));
+
+        let mut compress = flate2::Compress::new(Compression::default(), false);
+        let mut compressed = Vec::with_capacity(data.len());
+        compress.compress_vec(&data, &mut compressed, flate2::FlushCompress::Finish)?;
+
+        let mut crc = flate2::Crc::new();
+        crc.update(&data);
+
+        // total block size - 1, stored in the "BC" extra subfield
+        let bsize = (12 + 6 + compressed.len() + 8 - 1) as u16;
+
+        self.inner.write_all(&[0x1f, 0x8b, 0x08, 0x04])?; // ID1, ID2, CM, FLG(FEXTRA)
+        self.inner.write_all(&[0, 0, 0, 0])?; // MTIME
+        self.inner.write_all(&[0, 0xff])?; // XFL, OS (unknown)
+        self.inner.write_all(&6u16.to_le_bytes())?; // XLEN
+        self.inner.write_all(b"BC")?;
+        self.inner.write_all(&2u16.to_le_bytes())?;
+        self.inner.write_all(&bsize.to_le_bytes())?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(data.len() as u32).to_le_bytes())?;
+
+        self.compressed_bytes_written += (bsize as u64) + 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        self.flush_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, mut data: &[u8]) -> std::io::Result<usize> {
+        let total = data.len();
+        while !data.is_empty() {
+            let space = BGZF_BLOCK_SIZE - self.buf.len();
+            let take = space.min(data.len());
+            self.buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buf.len() == BGZF_BLOCK_SIZE {
+                self.flush_block()?;
+            }
+        }
+        Ok(total)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Tabix/SAM binning scheme: map a 0-based, half-open `[beg, end)` interval
+/// to the smallest bin that fully contains it.
+fn reg2bin(beg: i32, end: i32) -> u32 {
+    let end = end - 1;
+    if beg >> 14 == end >> 14 { return (((1 << 15) - 1) / 7 + (beg >> 14)) as u32; }
+    if beg >> 17 == end >> 17 { return (((1 << 12) - 1) / 7 + (beg >> 17)) as u32; }
+    if beg >> 20 == end >> 20 { return (((1 << 9) - 1) / 7 + (beg >> 20)) as u32; }
+    if beg >> 23 == end >> 23 { return (((1 << 6) - 1) / 7 + (beg >> 23)) as u32; }
+    if beg >> 26 == end >> 26 { return (((1 << 3) - 1) / 7 + (beg >> 26)) as u32; }
+    0
+}
+
+/// Linear index window size (16 kb), per the tabix specification.
+const TABIX_LINEAR_SHIFT: u32 = 14;
+
+/// Write coordinate-sorted fragment records as a bgzip-compressed file,
+/// building a tabix-style binning + linear index alongside it (written to
+/// the same path with a `.tbi` suffix appended) so downstream tools can do
+/// random-access region queries without decompressing the whole file.
+fn write_fragments_bgzf<P: AsRef<Path>>(records: &[FragmentRecord], out_file: P) -> Result<()> {
+    let f = File::create(&out_file)
+        .with_context(|| format!("cannot create file: {}", out_file.as_ref().display()))?;
+    let mut bgzf = BgzfWriter::new(BufWriter::new(f));
+
+    let mut chrom_order: Vec<String> = Vec::new();
+    let mut bins: HashMap<&str, HashMap<u32, Vec<(u64, u64)>>> = HashMap::new();
+    let mut linear: HashMap<&str, Vec<u64>> = HashMap::new();
+
+    for r in records {
+        if !bins.contains_key(r.chrom.as_str()) {
+            chrom_order.push(r.chrom.clone());
+            bins.insert(r.chrom.as_str(), HashMap::new());
+            linear.insert(r.chrom.as_str(), Vec::new());
+        }
+
+        let voffset_start = bgzf.voffset();
+        writeln!(bgzf, "{}\t{}\t{}\t{}\t{}", r.chrom, r.start, r.end, r.barcode, r.count)?;
+        let voffset_end = bgzf.voffset();
+
+        let bin = reg2bin(r.start as i32, r.end as i32);
+        bins.get_mut(r.chrom.as_str()).unwrap().entry(bin).or_insert_with(Vec::new)
+            .push((voffset_start, voffset_end));
+
+        // Index every 16kb window the record spans, not just the one its
+        // start falls in, so a query into the middle of a long fragment
+        // still finds it.
+        let start_window = (r.start >> TABIX_LINEAR_SHIFT) as usize;
+        let end_window = (r.end.saturating_sub(1) >> TABIX_LINEAR_SHIFT) as usize;
+        let lin = linear.get_mut(r.chrom.as_str()).unwrap();
+        if lin.len() <= end_window {
+            // Gap windows preceding this record's span inherit the last
+            // known offset, so a query landing there still starts
+            // scanning from a safe lower bound; the windows this record
+            // actually spans get its own (later) offset.
+            let gap_fill = lin.last().copied().unwrap_or(voffset_start);
+            while lin.len() < start_window {
+                lin.push(gap_fill);
+            }
+            while lin.len() <= end_window {
+                lin.push(voffset_start);
+            }
+        } else {
+            for w in start_window..=end_window {
+                if voffset_start < lin[w] { lin[w] = voffset_start; }
+            }
+        }
+    }
+    bgzf.finish()?;
+
+    let tbi_file = PathBuf::from(format!("{}.tbi", out_file.as_ref().display()));
+    write_tabix_index(&tbi_file, &chrom_order, &bins, &linear)?;
+    Ok(())
+}
+
+fn write_tabix_index<P: AsRef<Path>>(
+    out_file: P,
+    chrom_order: &[String],
+    bins: &HashMap<&str, HashMap<u32, Vec<(u64, u64)>>>,
+    linear: &HashMap<&str, Vec<u64>>,
+) -> Result<()> {
+    let f = File::create(&out_file)
+        .with_context(|| format!("cannot create file: {}", out_file.as_ref().display()))?;
+    let mut bgzf = BgzfWriter::new(BufWriter::new(f));
+
+    let names_blob: Vec<u8> = chrom_order.iter()
+        .flat_map(|n| n.bytes().chain(std::iter::once(0u8)))
+        .collect();
+
+    // Generic format (0) with the UCSC/0-based-coordinates flag (0x10000,
+    // `TI_PRESET_BED` in htslib) set, since fragment/BED records store
+    // 0-based half-open coordinates rather than SAM/VCF's 1-based ones.
+    const TI_FLAG_UCSC: i32 = 0x10000;
+    bgzf.write_all(b"TBI\x01")?;
+    bgzf.write_all(&(chrom_order.len() as i32).to_le_bytes())?;
+    bgzf.write_all(&TI_FLAG_UCSC.to_le_bytes())?; // format: generic, 0-based BED-like
+    bgzf.write_all(&1i32.to_le_bytes())?; // col_seq
+    bgzf.write_all(&2i32.to_le_bytes())?; // col_beg
+    bgzf.write_all(&3i32.to_le_bytes())?; // col_end
+    bgzf.write_all(&(b'#' as i32).to_le_bytes())?; // meta char
+    bgzf.write_all(&0i32.to_le_bytes())?; // lines to skip
+    bgzf.write_all(&(names_blob.len() as i32).to_le_bytes())?;
+    bgzf.write_all(&names_blob)?;
+
+    for chrom in chrom_order {
+        let chrom_bins = &bins[chrom.as_str()];
+        bgzf.write_all(&(chrom_bins.len() as i32).to_le_bytes())?;
+        for (bin, chunks) in chrom_bins.iter() {
+            bgzf.write_all(&bin.to_le_bytes())?;
+            bgzf.write_all(&(chunks.len() as i32).to_le_bytes())?;
+            for (cs, ce) in chunks {
+                bgzf.write_all(&cs.to_le_bytes())?;
+                bgzf.write_all(&ce.to_le_bytes())?;
+            }
+        }
+        let lin = &linear[chrom.as_str()];
+        bgzf.write_all(&(lin.len() as i32).to_le_bytes())?;
+        for ioff in lin {
+            bgzf.write_all(&ioff.to_le_bytes())?;
+        }
+    }
+    bgzf.finish()?;
+    Ok(())
+}
+
+/// Average fragment length (bp) assumed by RPGC normalization when scaling
+/// observed insertion counts up to genome coverage. This is a property of
+/// the normalization formula itself (see the `deeptools bamCoverage --normalizeUsing
+/// RPGC` definition) and is independent of the MACS2-style `extsize` used by
+/// the peak caller, so it is kept as its own constant rather than reusing
+/// [`PEAK_CALLING_EXTSIZE`].
+const RPGC_FRAGMENT_LENGTH: u64 = 200;
+
+/// Compute the scalar factor (`normalized_value = raw_count * scale`) for
+/// the requested [`NormMethod`], derived once from the aggregated per-bin
+/// counts so it can be applied uniformly to every bin.
+fn normalization_scale(
+    counts: &BTreeMap<usize, u32>,
+    resolution: usize,
+    genome_size: u64,
+    method: NormMethod,
+) -> f64 {
+    let total_count: f64 = counts.values().map(|&v| v as f64).sum();
+    match method {
+        NormMethod::None => 1.0,
+        NormMethod::CPM => 1e6 / total_count,
+        NormMethod::RPKM => 1e6 / (total_count * (resolution as f64 / 1000.0)),
+        NormMethod::BPM => {
+            // Each bin's length-normalized value is `count / resolution`;
+            // rescale so these sum to 1e6 across the genome. With uniform
+            // bin sizes this is algebraically the same scale as CPM, but is
+            // computed via the length-normalized sum to match the BPM
+            // definition (which otherwise applies to variable-length bins).
+            let density_sum: f64 = counts.values().map(|&v| v as f64 / resolution as f64).sum();
+            (1.0 / resolution as f64) / density_sum * 1e6
+        },
+        NormMethod::RPGC => (genome_size as f64) / (total_count * RPGC_FRAGMENT_LENGTH as f64),
+    }
+}
+
 /// Export TN5 insertions as bigwig files
-/// 
+///
 /// # Arguments
-/// 
+///
 /// * `insertions` - TN5 insertion matrix
-/// * `genome_index` - 
-/// * `chrom_sizes` - 
+/// * `genome_index` -
+/// * `chrom_sizes` -
 fn export_insertions_as_bigwig(
     insertions: &CsrMatrix<u8>,
     genome_index: &GBaseIndex,
     chrom_sizes: &HashMap<String, u32>,
     resolution: usize,
+    normalization: NormMethod,
+    blacklist: Option<&Blacklist>,
     out_file: String,
 )
 {
-    // aggregate insertion counts
+    // aggregate insertion counts, dropping any insertion that overlaps a
+    // blacklist interval before it contributes to the pileup.
     let mut counts: BTreeMap<usize, u32> = BTreeMap::new();
     insertions.col_indices().into_iter().zip(insertions.values()).for_each(|(i, v)| {
+        if let Some(b) = blacklist {
+            let region = genome_index.lookup_region(*i);
+            if b.overlaps(region.chrom(), region.start()) { return; }
+        }
         let e = counts.entry(*i / resolution).or_insert(0);
         *e += *v as u32;
     });
 
-    // compute normalization factor
-    let total_count: u32 = counts.values().sum();
-    let norm_factor = ((total_count as f32) / 1000000.0) *
-        ((resolution as f32) / 1000.0);
+    let genome_size: u64 = chrom_sizes.values().map(|&s| s as u64).sum();
+    let scale = normalization_scale(&counts, resolution, genome_size, normalization);
 
     // Make BedGraph
     let mut bedgraph: Vec<BedGraph<f32>> = counts.into_iter().map(move |(k, v)| {
         let mut region = genome_index.lookup_region(k * resolution);
         region.set_end(region.start() + resolution as u64);
-        BedGraph::from_bed(&region, (v as f32) / norm_factor)
+        BedGraph::from_bed(&region, (v as f64 * scale) as f32)
     }).group_by(|x| (x.chrom().to_string(), x.value)).into_iter().map(|(_, mut groups)| {
         let mut first = groups.next().unwrap();
         if let Some(last) = groups.last() {
@@ -307,55 +958,358 @@ fn export_insertions_as_bigwig(
     ).unwrap();
 }
 
-fn macs2<P1, P2, P3>(
+/// Default parameters of the peak caller, matching the conventions used
+/// throughout the single-cell ATAC-seq literature (and formerly passed to
+/// `macs2 --nomodel --shift -100 --extsize 200`).
+const PEAK_CALLING_SHIFT: i64 = -100;
+const PEAK_CALLING_EXTSIZE: i64 = 200;
+/// Window sizes (in bp) used to estimate the local Poisson background, in
+/// addition to the genome-wide mean.
+const LOCAL_LAMBDA_WINDOWS: [u64; 3] = [1000, 5000, 10000];
+
+/// A single Tn5 insertion, extended into a `extsize`-bp fragment and
+/// shifted by `shift`, clamped to the chromosome boundaries.
+fn extend_insertion(pos: u64, chrom_len: u64) -> (u64, u64) {
+    let start = (pos as i64 + PEAK_CALLING_SHIFT).max(0) as u64;
+    let end = (start + PEAK_CALLING_EXTSIZE as u64).min(chrom_len);
+    (start.min(chrom_len), end)
+}
+
+/// Read a (possibly gzip-compressed) BED file of Tn5 insertions and return,
+/// for each chromosome, the sorted list of 5' insertion positions.
+fn read_insertion_positions<P: AsRef<Path>>(bed_file: P) -> Result<HashMap<String, Vec<u64>>> {
+    let f = File::open(&bed_file)
+        .with_context(|| format!("cannot open bed file: {}", bed_file.as_ref().display()))?;
+    let reader: Box<dyn BufRead> = if bed_file.as_ref().extension().map_or(false, |x| x == "gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(f)))
+    } else {
+        Box::new(BufReader::new(f))
+    };
+
+    let mut positions: HashMap<String, Vec<u64>> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let chrom = fields.next().context("bed record is missing chrom field")?;
+        let start: u64 = fields.next()
+            .context("bed record is missing start field")?
+            .parse().context("bed start is not a valid integer")?;
+        positions.entry(chrom.to_string()).or_insert_with(Vec::new).push(start);
+    }
+    positions.values_mut().for_each(|p| p.sort_unstable());
+    Ok(positions)
+}
+
+/// Build a run-length-encoded pileup track (`[start, end)` -> coverage) for
+/// a chromosome from its sorted list of insertion positions, by extending
+/// each insertion into a fragment and summing coverage with a difference
+/// array.
+fn build_pileup(positions: &[u64], chrom_len: u64) -> Vec<(u64, u64, u32)> {
+    let mut diff: BTreeMap<u64, i32> = BTreeMap::new();
+    for &pos in positions {
+        let (start, end) = extend_insertion(pos, chrom_len);
+        if start < end {
+            *diff.entry(start).or_insert(0) += 1;
+            *diff.entry(end).or_insert(0) -= 1;
+        }
+    }
+
+    let mut pileup = Vec::new();
+    let mut prev_pos = 0u64;
+    let mut cur_val: i32 = 0;
+    for (&pos, &delta) in diff.iter() {
+        if pos > prev_pos && cur_val > 0 {
+            pileup.push((prev_pos, pos, cur_val as u32));
+        }
+        cur_val += delta;
+        prev_pos = pos;
+    }
+    pileup
+}
+
+/// Estimate the mean per-base coverage in a window around `center`, used as
+/// the local Poisson background `λ_local`: the number of insertion positions
+/// falling in `[center - window / 2, center + window / 2)`, times `extsize`,
+/// divided by `window`.
+fn local_lambda(positions: &[u64], center: u64, window: u64) -> f64 {
+    let half = window / 2;
+    let lo = center.saturating_sub(half);
+    let hi = center + half;
+    let lo_idx = positions.partition_point(|&p| p < lo);
+    let hi_idx = positions.partition_point(|&p| p < hi);
+    let n = (hi_idx - lo_idx) as f64;
+    n * (PEAK_CALLING_EXTSIZE as f64) / (window as f64)
+}
+
+/// Regularized upper incomplete gamma function `Q(a, x)`, computed via the
+/// series expansion of `P(a, x)` or the continued fraction for `Q(a, x)`
+/// depending on which converges faster (Numerical Recipes §6.2).
+fn regularized_gamma_q(a: f64, x: f64) -> f64 {
+    if x < a + 1.0 {
+        1.0 - gamma_p_series(a, x)
+    } else {
+        gamma_q_cf(a, x)
+    }
+}
+
+fn log_gamma(x: f64) -> f64 {
+    const COF: [f64; 6] = [
+        76.18009172947146, -86.50532032941677, 24.01409824083091,
+        -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5,
+    ];
+    let mut y = x;
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let mut ser = 1.000000000190015;
+    for c in COF.iter() {
+        y += 1.0;
+        ser += c / y;
+    }
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+fn gamma_p_series(a: f64, x: f64) -> f64 {
+    if x <= 0.0 { return 0.0; }
+    let gln = log_gamma(a);
+    let mut ap = a;
+    let mut sum = 1.0 / a;
+    let mut del = sum;
+    for _ in 0..200 {
+        ap += 1.0;
+        del *= x / ap;
+        sum += del;
+        if del.abs() < sum.abs() * 1e-12 { break; }
+    }
+    sum * (-x + a * x.ln() - gln).exp()
+}
+
+fn gamma_q_cf(a: f64, x: f64) -> f64 {
+    let gln = log_gamma(a);
+    let tiny = 1e-300;
+    let mut b = x + 1.0 - a;
+    let mut c = 1.0 / tiny;
+    let mut d = 1.0 / b;
+    let mut h = d;
+    for i in 1..200 {
+        let an = -(i as f64) * (i as f64 - a);
+        b += 2.0;
+        d = an * d + b;
+        if d.abs() < tiny { d = tiny; }
+        c = b + an / c;
+        if c.abs() < tiny { c = tiny; }
+        d = 1.0 / d;
+        let del = d * c;
+        h *= del;
+        if (del - 1.0).abs() < 1e-12 { break; }
+    }
+    (-x + a * x.ln() - gln).exp() * h
+}
+
+/// Upper-tail Poisson p-value `P(X >= observed | lambda)`.
+fn poisson_pvalue(observed: u32, lambda: f64) -> f64 {
+    if observed == 0 { return 1.0; }
+    if lambda <= 0.0 { return 0.0; }
+    // For integer k, Q(k, lambda) = P(X <= k-1), so P(X >= observed) is its
+    // complement rather than Q(observed, lambda) itself.
+    (1.0 - regularized_gamma_q(observed as f64, lambda)).clamp(0.0, 1.0)
+}
+
+/// Benjamini-Hochberg adjustment, returning q-values in the same order as
+/// the input p-values.
+fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let n = p_values.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| p_values[i].partial_cmp(&p_values[j]).unwrap());
+
+    let mut q_sorted = vec![0.0; n];
+    let mut min_so_far = 1.0;
+    for (rank, &idx) in order.iter().enumerate().rev() {
+        let q = p_values[idx] * n as f64 / (rank as f64 + 1.0);
+        min_so_far = min_so_far.min(q).min(1.0);
+        q_sorted[rank] = min_so_far;
+    }
+
+    let mut q_values = vec![0.0; n];
+    for (rank, &idx) in order.iter().enumerate() {
+        q_values[idx] = q_sorted[rank];
+    }
+    q_values
+}
+
+struct Candidate {
+    chrom: String,
+    start: u64,
+    end: u64,
+    summit: u64,
+    pileup: u32,
+    neg_log10_pvalue: f64,
+    neg_log10_qvalue: f64,
+}
+
+/// Call peaks natively on a single group's insertion BED file, following
+/// the MACS2 `--nomodel --call-summits` strategy: build a pileup track,
+/// estimate a local Poisson background, score every covered position, adjust
+/// for multiple testing, and merge adjacent significant bins into peaks.
+fn call_peaks<P1, P3>(
     bed_file: P1,
     q_value: f64,
-    genome_size: u64,
-    tmp_dir: P2,
+    chrom_sizes: &HashMap<String, u64>,
     out_file: P3,
 ) -> Result<()>
 where
     P1: AsRef<Path>,
-    P2: AsRef<Path>,
     P3: AsRef<Path>,
 {
-    let dir = Builder::new().tempdir_in(tmp_dir)?;
-
-    Command::new("macs2").args([
-        "callpeak",
-        "-f", "BED",
-        "-t", bed_file.as_ref().to_str().unwrap(),
-        "--keep-dup", "all",
-        "--outdir", format!("{}", dir.path().display()).as_str(),
-        "--qvalue", format!("{}", q_value).as_str(),
-        "-g", format!("{}", (genome_size as f64 * 0.9).round()).as_str(),
-        "--call-summits",
-        "--nomodel", "--shift", "-100", "--extsize", "200",
-        "--nolambda",
-        "--tempdir", format!("{}", dir.path().display()).as_str(),
-    ]).output().context("macs2 command did not exit properly")?;
-
-    let reader = BufReader::new(File::open(
-        dir.path().join("NA_peaks.narrowPeak"))
-            .context("NA_peaks.narrowPeak: cannot find the peak file")?
-    );
-    let mut writer: Box<dyn Write> = if out_file.as_ref().extension().unwrap() == "gz" {
+    let positions = read_insertion_positions(bed_file)?;
+    let total_fragments: usize = positions.values().map(|v| v.len()).sum();
+    let genome_size: u64 = chrom_sizes.values().sum();
+    let lambda_bg = (total_fragments as f64) * (PEAK_CALLING_EXTSIZE as f64)
+        / (genome_size.max(1) as f64);
+
+    let mut candidates = Vec::new();
+    let mut p_values = Vec::new();
+    for (chrom, pos) in positions.iter() {
+        let chrom_len = *chrom_sizes.get(chrom)
+            .with_context(|| format!("chromosome not found: {}", chrom))?;
+        for (start, end, pileup) in build_pileup(pos, chrom_len) {
+            let mid = start + (end - start) / 2;
+            let lambda_local = LOCAL_LAMBDA_WINDOWS.iter()
+                .map(|&w| local_lambda(pos, mid, w))
+                .fold(lambda_bg, f64::max);
+            let p = poisson_pvalue(pileup, lambda_local);
+            p_values.push(p);
+            candidates.push(Candidate {
+                chrom: chrom.clone(), start, end, summit: mid, pileup,
+                neg_log10_pvalue: -p.max(f64::MIN_POSITIVE).log10(),
+                neg_log10_qvalue: 0.0,
+            });
+        }
+    }
+
+    let q_values = benjamini_hochberg(&p_values);
+    candidates.iter_mut().zip(q_values.iter()).for_each(|(c, &q)| {
+        c.neg_log10_qvalue = -q.max(f64::MIN_POSITIVE).log10();
+    });
+    candidates.retain(|c| 10f64.powf(-c.neg_log10_qvalue) <= q_value);
+    candidates.sort_by(|a, b| a.chrom.cmp(&b.chrom).then(a.start.cmp(&b.start)));
+
+    // Merge adjacent (or overlapping) significant bins into peaks, keeping
+    // track of the position of maximum pileup as the summit.
+    let mut peaks: Vec<Candidate> = Vec::new();
+    for c in candidates.into_iter() {
+        if let Some(last) = peaks.last_mut() {
+            if last.chrom == c.chrom && c.start <= last.end {
+                last.end = last.end.max(c.end);
+                if c.pileup > last.pileup {
+                    last.pileup = c.pileup;
+                    last.summit = c.summit;
+                }
+                last.neg_log10_pvalue = last.neg_log10_pvalue.max(c.neg_log10_pvalue);
+                last.neg_log10_qvalue = last.neg_log10_qvalue.max(c.neg_log10_qvalue);
+                continue;
+            }
+        }
+        peaks.push(c);
+    }
+
+    let mut writer: Box<dyn Write> = if out_file.as_ref().extension().map_or(false, |x| x == "gz") {
         Box::new(BufWriter::new(GzEncoder::new(
-            File::create(out_file)?,
+            File::create(&out_file)?,
             Compression::default(),
         )))
     } else {
-        Box::new(BufWriter::new(File::create(out_file)?))
+        Box::new(BufWriter::new(File::create(&out_file)?))
     };
-    for x in reader.lines() {
-        let x_ = x?;
-        let mut strs: Vec<_> = x_.split("\t").collect();
-        if strs[4].parse::<u64>().unwrap() > 1000 {
-            strs[4] = "1000";
-        }
-        let line: String = strs.into_iter().intersperse("\t").collect();
-        write!(writer, "{}\n", line)?;
+    for (i, p) in peaks.iter().enumerate() {
+        let score = (p.pileup as u64).min(1000);
+        writeln!(
+            writer,
+            "{}\t{}\t{}\tpeak_{}\t{}\t.\t{}\t{}\t{}\t{}",
+            p.chrom, p.start, p.end, i, score,
+            p.pileup, p.neg_log10_pvalue, p.neg_log10_qvalue,
+            p.summit - p.start,
+        )?;
+    }
+    Ok(())
+}
+
+/// Deterministically bucket position `i` into one of `n` pseudo-replicates.
+/// A hash of the position (rather than `i % n`) is used so that cells are
+/// not split in a way that correlates with their original ordering (e.g.
+/// if cells happen to be grouped by sequencing batch).
+fn pseudo_random_bucket(i: usize, n: usize) -> usize {
+    let mut x = (i as u64) ^ 0x9E3779B97F4A7C15;
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^= x >> 33;
+    (x as usize) % n
+}
+
+fn mean(xs: &[f64]) -> f64 {
+    xs.iter().sum::<f64>() / xs.len() as f64
+}
+
+fn std_deviation(xs: &[f64]) -> f64 {
+    let m = mean(xs);
+    (xs.iter().map(|x| (x - m).powi(2)).sum::<f64>() / xs.len() as f64).sqrt()
+}
+
+fn reciprocal_overlap(a: (u64, u64), b: (u64, u64), min_fraction: f64) -> bool {
+    let overlap = (a.1.min(b.1) as i64) - (a.0.max(b.0) as i64);
+    if overlap <= 0 { return false; }
+    let overlap = overlap as f64;
+    let len_a = (a.1 - a.0) as f64;
+    let len_b = (b.1 - b.0) as f64;
+    overlap / len_a >= min_fraction && overlap / len_b >= min_fraction
+}
+
+/// Filter the pooled peaks in `pooled_file` down to those recovered (by
+/// reciprocal overlap) in at least `qc.min_replicates` of the peak sets in
+/// `replicate_files`, appending the mean and standard deviation of the
+/// recovering replicates' scores as two extra narrowPeak columns, and
+/// overwriting `pooled_file` in place.
+/// Filter the pooled narrowPeak records in `pooled_file` down to those
+/// recovered (by reciprocal overlap) in at least `qc.min_replicates` of the
+/// peak sets in `replicate_files`, appending the mean and standard
+/// deviation of the recovering replicates' scores as two extra columns
+/// (11-12) onto the existing narrowPeak record rather than replacing it.
+fn filter_by_reproducibility<P: AsRef<Path>>(
+    pooled_file: P,
+    replicate_files: &[PathBuf],
+    qc: &ReplicateQC,
+) -> Result<()> {
+    let pooled_lines = read_lines(&pooled_file)?;
+    let pooled: Vec<PeakRecord> = pooled_lines.iter()
+        .map(|line| parse_peak_record(line))
+        .collect::<Result<_>>()?;
+    let replicates: Vec<Vec<PeakRecord>> = replicate_files.iter()
+        .map(read_peak_records)
+        .collect::<Result<_>>()?;
+
+    let mut writer: Box<dyn Write> = if pooled_file.as_ref().extension().map_or(false, |x| x == "gz") {
+        Box::new(BufWriter::new(GzEncoder::new(
+            File::create(&pooled_file)?,
+            Compression::default(),
+        )))
+    } else {
+        Box::new(BufWriter::new(File::create(&pooled_file)?))
+    };
+
+    let mut kept = 0;
+    for (line, peak) in pooled_lines.iter().zip(pooled.iter()) {
+        let scores: Vec<f64> = replicates.iter().filter_map(|rep| {
+            rep.iter()
+                .find(|r| r.chrom == peak.chrom && reciprocal_overlap(
+                    (peak.start, peak.end), (r.start, r.end), qc.overlap_fraction,
+                ))
+                .map(|r| r.score as f64)
+        }).collect();
+        if scores.len() < qc.min_replicates { continue; }
+        kept += 1;
+        writeln!(writer, "{}\t{}\t{}", line, mean(&scores), std_deviation(&scores))?;
     }
+    eprintln!("reproducibility filter: kept {} of {} pooled peaks", kept, pooled.len());
     Ok(())
 }
  
\ No newline at end of file